@@ -1,14 +1,108 @@
 use clap::{App, Arg};
 use walkdir::WalkDir;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{PathBuf, Path};
 use std::{fs, io};
 use sha1::{Sha1, Digest};
 use std::io::{Error, Write};
-use lol_html::{HtmlRewriter, Settings, element};
+use lol_html::{HtmlRewriter, Settings, element, text};
+use lol_html::html_content::ContentType;
 use url::Url;
 use std::ffi::OsStr;
+use std::cell::RefCell;
+use sha2::{Sha256, Sha384, Sha512};
 
+#[derive(Copy, Clone)]
+enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    fn parse(s: &str) -> Option<HashAlgorithm> {
+        match s {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha512" => Some(HashAlgorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+struct AssetHash {
+    hash: String,
+    sri: String,
+}
+
+struct BrokenReference {
+    file: String,
+    attribute: String,
+    target: String,
+}
+
+struct DomainPolicy {
+    base_host: Option<String>,
+    rewrite: HashSet<String>,
+    ignore: HashSet<String>,
+}
+
+impl DomainPolicy {
+    fn new(base_url: &Url, rewrite: HashSet<String>, ignore: HashSet<String>) -> DomainPolicy {
+        DomainPolicy {
+            base_host: base_url.host_str().map(String::from),
+            rewrite,
+            ignore,
+        }
+    }
+
+    fn is_allowed(&self, host: &str) -> bool {
+        if self.ignore.contains(host) {
+            return false;
+        }
+        if self.rewrite.is_empty() {
+            self.base_host.as_deref() == Some(host)
+        } else {
+            self.rewrite.contains(host)
+        }
+    }
+}
+
+fn rewrite_srcset(value: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>, broken: &RefCell<Vec<BrokenReference>>, file: &str, attribute: &str) -> String {
+    value.split(',').map(|entry| {
+        let leading = &entry[..entry.len() - entry.trim_start().len()];
+        let trailing = &entry[entry.trim_end().len()..];
+        let core = entry.trim();
+        if core.is_empty() {
+            return String::from(entry);
+        }
+
+        let (url_part, descriptor) = match core.find(char::is_whitespace) {
+            Some(idx) => (&core[..idx], Some(core[idx..].trim())),
+            None => (core, None),
+        };
+
+        check_broken(broken, file, attribute, url_part, domains, assets_path, assets);
+        let new_url = match_asset(url_part, domains, assets_path, assets);
+
+        match descriptor {
+            Some(d) if !d.is_empty() => format!("{}{} {}{}", leading, new_url, d, trailing),
+            _ => format!("{}{}{}", leading, new_url, trailing),
+        }
+    }).collect::<Vec<_>>().join(",")
+}
+
+fn check_broken(broken: &RefCell<Vec<BrokenReference>>, file: &str, attribute: &str, src: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>) {
+    if let Some(local) = resolve_local_path(src, domains, assets_path) {
+        if !assets.contains_key(&local) {
+            broken.borrow_mut().push(BrokenReference {
+                file: file.to_string(),
+                attribute: attribute.to_string(),
+                target: local,
+            });
+        }
+    }
+}
 
 fn main() {
     let matches = App::new("cache-buster")
@@ -37,6 +131,44 @@ fn main() {
             .takes_value(true)
             .multiple(true)
             .value_delimiter(","))
+        .arg(Arg::with_name("manifest")
+            .short("m")
+            .long("manifest")
+            .value_name("FILE")
+            .help("Writes a JSON manifest mapping original asset paths to their hashed paths")
+            .takes_value(true))
+        .arg(Arg::with_name("integrity")
+            .long("integrity")
+            .help("Adds Subresource Integrity (integrity/crossorigin) attributes to rewritten script/stylesheet tags"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Exits with a non-zero status if any HTML/CSS asset reference can't be resolved"))
+        .arg(Arg::with_name("hash_algorithm")
+            .long("hash-algorithm")
+            .value_name("ALGORITHM")
+            .help("Hash algorithm used to bust the cache")
+            .takes_value(true)
+            .possible_values(&["sha1", "sha256", "sha512"])
+            .default_value("sha1"))
+        .arg(Arg::with_name("hash_length")
+            .long("hash-length")
+            .value_name("N")
+            .help("Truncates the hash to its first N characters (default: keep the full hash)")
+            .takes_value(true))
+        .arg(Arg::with_name("rewrite_domains")
+            .long("rewrite-domains")
+            .value_name("LIST")
+            .help("Comma-separated hostnames whose absolute asset URLs should be rewritten; when given, --base-url's host is only rewritten if listed here (default: only --base-url's host)")
+            .takes_value(true)
+            .multiple(true)
+            .value_delimiter(","))
+        .arg(Arg::with_name("ignore_domains")
+            .long("ignore-domains")
+            .value_name("LIST")
+            .help("Comma-separated hostnames whose absolute asset URLs should never be rewritten")
+            .takes_value(true)
+            .multiple(true)
+            .value_delimiter(","))
         .get_matches();
 
     let assets = normalize_path(matches.value_of("assets").unwrap());
@@ -49,24 +181,63 @@ fn main() {
         Ok(url) => url
     };
     let exts = matches.values_of_lossy("ignore_assets_extensions").unwrap_or_else(|| vec![]);
+    let manifest = matches.value_of("manifest");
+    let integrity = matches.is_present("integrity");
+    let strict = matches.is_present("strict");
+    let hash_algorithm = HashAlgorithm::parse(matches.value_of("hash_algorithm").unwrap()).unwrap();
+    let hash_length = match matches.value_of("hash_length") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                eprintln!("Error parsing hash-length: expected a positive integer");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let rewrite_domains: HashSet<String> = matches.values_of_lossy("rewrite_domains").unwrap_or_default().into_iter().collect();
+    let ignore_domains: HashSet<String> = matches.values_of_lossy("ignore_domains").unwrap_or_default().into_iter().collect();
+    let domains = DomainPolicy::new(&base_url, rewrite_domains, ignore_domains);
 
-    match execute(pwd, assets, &base_url, exts) {
+    match execute(pwd, assets, &domains, exts, manifest, integrity, strict, hash_algorithm, hash_length) {
         Ok(_) => {}
         Err(e) => panic!(format!("Error: {}", e))
     }
 }
 
-fn hash_file(p: &Path) -> Result<String, Error> {
+fn hash_file(p: &Path, algorithm: HashAlgorithm) -> Result<String, Error> {
     let mut file = fs::File::open(p)?;
-    let mut hasher = Sha1::new();
+    let hex = match algorithm {
+        HashAlgorithm::Sha1 => {
+            let mut hasher = Sha1::new();
+            io::copy(&mut file, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            io::copy(&mut file, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+        HashAlgorithm::Sha512 => {
+            let mut hasher = Sha512::new();
+            io::copy(&mut file, &mut hasher)?;
+            format!("{:x}", hasher.finalize())
+        }
+    };
+    Ok(hex)
+}
+
+fn compute_sri(p: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(p)?;
+    let mut hasher = Sha384::new();
     io::copy(&mut file, &mut hasher)?;
-    let hash = hasher.finalize();
-    Ok(format!("{:x}", hash))
+    let digest = hasher.finalize();
+    Ok(base64::encode(digest))
 }
 
-fn update_asset(path_str: &str, assets: &HashMap<String, String>) -> String {
+fn update_asset(path_str: &str, assets: &HashMap<String, AssetHash>) -> String {
     let hash = match assets.get(path_str) {
-        Some(h) => h,
+        Some(a) => &a.hash,
         None => return String::from(path_str)
     };
 
@@ -89,7 +260,26 @@ fn normalize_path(path: &str) -> &str {
     }
 }
 
-fn match_asset(src: &str, base_url: &Url, assets_path: &str, assets: &HashMap<String, String>) -> String {
+fn resolve_local_path(src: &str, domains: &DomainPolicy, assets_path: &str) -> Option<String> {
+    if src.starts_with("http://") || src.starts_with("https://") {
+        let link = Url::parse(src).ok()?;
+        let path = normalize_path(link.path());
+        return if link.host_str().map(|h| domains.is_allowed(h)).unwrap_or(false) && path.starts_with(assets_path) {
+            Some(String::from(path))
+        } else {
+            None
+        };
+    }
+
+    let src = normalize_path(src);
+    if src.starts_with(assets_path) {
+        Some(String::from(src))
+    } else {
+        None
+    }
+}
+
+fn match_asset(src: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>) -> String {
     if src.starts_with("http://") || src.starts_with("https://") {
         let mut link = match Url::parse(src) {
             Ok(u) => u,
@@ -97,7 +287,7 @@ fn match_asset(src: &str, base_url: &Url, assets_path: &str, assets: &HashMap<St
         };
         let path = normalize_path(link.path());
 
-        if link.has_host() && link.host_str().eq(&base_url.host_str()) && path.starts_with(assets_path) {
+        if link.host_str().map(|h| domains.is_allowed(h)).unwrap_or(false) && path.starts_with(assets_path) {
             let fixed_path = update_asset(path, assets);
             link.set_path(&fixed_path);
             return link.into_string();
@@ -112,25 +302,250 @@ fn match_asset(src: &str, base_url: &Url, assets_path: &str, assets: &HashMap<St
     String::from(src)
 }
 
-fn execute(source: PathBuf, assets_path: &str, base_url: &Url, ignored_exts: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+fn resolve_relative(base_dir: &str, rel: &str) -> String {
+    let mut parts: Vec<&str> = if base_dir.is_empty() { vec![] } else { base_dir.split('/').collect() };
+    for segment in rel.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => { parts.pop(); }
+            s => parts.push(s),
+        }
+    }
+    parts.join("/")
+}
+
+fn rewrite_css_reference(raw: &str, css_dir: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>, broken: &RefCell<Vec<BrokenReference>>, file: &str) -> String {
+    let trimmed = raw.trim();
+    let (quote, inner) = match trimmed.chars().next() {
+        Some(c) if (c == '\'' || c == '"') && trimmed.len() >= 2 && trimmed.ends_with(c) =>
+            (Some(c), &trimmed[1..trimmed.len() - 1]),
+        _ => (None, trimmed),
+    };
+
+    if inner.is_empty() || inner.starts_with("data:") || inner.starts_with('#') {
+        return String::from(raw);
+    }
+
+    let new_inner = if inner.starts_with("http://") || inner.starts_with("https://") {
+        check_broken(broken, file, "url()", inner, domains, assets_path, assets);
+        match_asset(inner, domains, assets_path, assets)
+    } else if let Some(stripped) = inner.strip_prefix('/') {
+        check_broken(broken, file, "url()", stripped, domains, assets_path, assets);
+        match_asset(stripped, domains, assets_path, assets)
+    } else {
+        let resolved = resolve_relative(css_dir, inner);
+        check_broken(broken, file, "url()", &resolved, domains, assets_path, assets);
+        match_asset(&resolved, domains, assets_path, assets)
+    };
+
+    match quote {
+        Some(q) => format!("{}{}{}", q, new_inner, q),
+        None => new_inner,
+    }
+}
+
+fn find_ci(haystack: &str, needle: &str) -> Option<usize> {
+    let needle = needle.as_bytes();
+    haystack.as_bytes().windows(needle.len()).position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+fn starts_with_ci(haystack: &str, needle: &str) -> bool {
+    haystack.len() >= needle.len() && haystack.as_bytes()[..needle.len()].eq_ignore_ascii_case(needle.as_bytes())
+}
+
+fn process_url_token(rest: &str, css_dir: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>, broken: &RefCell<Vec<BrokenReference>>, file: &str) -> (usize, String) {
+    let close = match rest.find(')') {
+        Some(idx) => idx,
+        None => return (4, String::from("url(")),
+    };
+    let rewritten = rewrite_css_reference(&rest[4..close], css_dir, domains, assets_path, assets, broken, file);
+    (close + 1, format!("url({})", rewritten))
+}
+
+fn process_import(rest: &str, css_dir: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>, broken: &RefCell<Vec<BrokenReference>>, file: &str) -> (usize, String) {
+    let url_idx = find_ci(rest, "url(");
+    let quote_idx = rest[7..].find(['\'', '"']).map(|i| i + 7);
+
+    match (quote_idx, url_idx) {
+        (Some(q), u) if u.is_none() || q < u.unwrap() => {
+            let quote_char = rest[q..].chars().next().unwrap();
+            let close = match rest[q + 1..].find(quote_char) {
+                Some(idx) => q + 1 + idx,
+                None => return (7, String::from("@import")),
+            };
+            let rewritten = rewrite_css_reference(&rest[q..=close], css_dir, domains, assets_path, assets, broken, file);
+            (close + 1, format!("@import{}{}", &rest[7..q], rewritten))
+        }
+        (_, Some(u)) => {
+            let (consumed, rewritten) = process_url_token(&rest[u..], css_dir, domains, assets_path, assets, broken, file);
+            (u + consumed, format!("@import{}{}", &rest[7..u], rewritten))
+        }
+        _ => (7, String::from("@import")),
+    }
+}
+
+fn process_css(content: &str, css_dir: &str, domains: &DomainPolicy, assets_path: &str, assets: &HashMap<String, AssetHash>, broken: &RefCell<Vec<BrokenReference>>, file: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    loop {
+        let next_url = find_ci(rest, "url(");
+        let next_import = find_ci(rest, "@import");
+        let idx = match (next_url, next_import) {
+            (None, None) => {
+                result.push_str(rest);
+                break;
+            }
+            (Some(u), Some(im)) => u.min(im),
+            (Some(u), None) => u,
+            (None, Some(im)) => im,
+        };
+        result.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        let (consumed, rewritten) = if starts_with_ci(rest, "@import") {
+            process_import(rest, css_dir, domains, assets_path, assets, broken, file)
+        } else {
+            process_url_token(rest, css_dir, domains, assets_path, assets, broken, file)
+        };
+        result.push_str(&rewritten);
+        rest = &rest[consumed..];
+    }
+    result
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn write_manifest(path: &Path, manifest: &HashMap<String, String>) -> io::Result<()> {
+    let mut entries: Vec<(&String, &String)> = manifest.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut json = String::from("{\n");
+    for (i, (original, hashed)) in entries.iter().enumerate() {
+        json.push_str(&format!("  \"{}\": \"{}\"", json_escape(original), json_escape(hashed)));
+        if i != entries.len() - 1 {
+            json.push(',');
+        }
+        json.push('\n');
+    }
+    json.push_str("}\n");
+
+    fs::write(path, json)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute(source: PathBuf, assets_path: &str, domains: &DomainPolicy, ignored_exts: Vec<String>, manifest_path: Option<&str>, integrity: bool, strict: bool, hash_algorithm: HashAlgorithm, hash_length: Option<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let broken = RefCell::new(Vec::new());
     let mut assets_hashes = HashMap::new();
+    let mut seen_hashes: HashMap<String, String> = HashMap::new();
     for entry in WalkDir::new(assets_path)
         .into_iter()
         .map(|el| el.unwrap())
         .filter(|el| el.metadata().unwrap().is_file())
         .filter(|el| !ignored_exts.contains(&String::from(el.path().extension().unwrap().to_str().unwrap()))) {
         let path = entry.path();
-        let hash = match hash_file(path) {
+        let mut hash = match hash_file(path, hash_algorithm) {
             Ok(hash) => hash,
             Err(e) => {
                 println!("Error hashing {}: {}", path.to_str().unwrap(), e);
                 std::process::exit(1);
             }
         };
+        if let Some(len) = hash_length {
+            hash.truncate(len.min(hash.len()));
+        }
+        let sri = match compute_sri(path) {
+            Ok(sri) => sri,
+            Err(e) => {
+                println!("Error computing integrity digest for {}: {}", path.to_str().unwrap(), e);
+                std::process::exit(1);
+            }
+        };
         let path_str = path.to_str().unwrap();
-        assets_hashes.insert(path_str.to_string(), hash);
+        if let Some(existing) = seen_hashes.get(&hash) {
+            println!("Error: {} and {} hash to the same value ({}); try a longer --hash-length", existing, path_str, hash);
+            std::process::exit(1);
+        }
+        seen_hashes.insert(hash.clone(), path_str.to_string());
+        assets_hashes.insert(path_str.to_string(), AssetHash { hash, sri });
     };
 
+    let css_paths: Vec<String> = assets_hashes.keys()
+        .filter(|path_str| Path::new(path_str).extension().and_then(OsStr::to_str) == Some("css"))
+        .cloned()
+        .collect();
+    let mut css_sources: HashMap<String, String> = HashMap::new();
+    for path_str in &css_paths {
+        css_sources.insert(path_str.clone(), fs::read_to_string(path_str)?);
+    }
+
+    // A stylesheet can @import/url() another stylesheet whose own hash is
+    // still changing this pass, so one sweep over css_paths isn't enough:
+    // keep re-deriving every CSS file from its original source (not the
+    // previous pass's rewritten output, which would double-rewrite already
+    // hashed references) until hashes stop moving. css_paths.len() passes
+    // is enough for any acyclic chain; anything longer means an import cycle,
+    // so bail out rather than loop forever.
+    let max_passes = css_paths.len() + 1;
+    for pass in 0..max_passes {
+        broken.borrow_mut().clear();
+        let mut changed = false;
+        for path_str in &css_paths {
+            let path = Path::new(path_str);
+            let css_dir = path.parent().and_then(Path::to_str).unwrap_or("");
+            let rewritten = process_css(&css_sources[path_str], css_dir, domains, assets_path, &assets_hashes, &broken, path_str);
+            fs::write(path, rewritten)?;
+
+            let mut hash = match hash_file(path, hash_algorithm) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    println!("Error hashing {}: {}", path_str, e);
+                    std::process::exit(1);
+                }
+            };
+            if let Some(len) = hash_length {
+                hash.truncate(len.min(hash.len()));
+            }
+            let sri = match compute_sri(path) {
+                Ok(sri) => sri,
+                Err(e) => {
+                    println!("Error computing integrity digest for {}: {}", path_str, e);
+                    std::process::exit(1);
+                }
+            };
+
+            if let Some(previous) = assets_hashes.get(path_str) {
+                if previous.hash != hash {
+                    changed = true;
+                }
+                seen_hashes.remove(&previous.hash);
+            } else {
+                changed = true;
+            }
+            if let Some(existing) = seen_hashes.get(&hash) {
+                println!("Error: {} and {} hash to the same value ({}); try a longer --hash-length", existing, path_str, hash);
+                std::process::exit(1);
+            }
+            seen_hashes.insert(hash.clone(), path_str.clone());
+            assets_hashes.insert(path_str.clone(), AssetHash { hash, sri });
+        }
+        if !changed {
+            break;
+        }
+        if pass == max_passes - 1 {
+            println!("Warning: CSS cross-references did not converge after {} passes (possible @import cycle); using the last computed hashes", max_passes);
+        }
+    }
+
     for entry in WalkDir::new(source) {
         let read_entry = entry?;
         let filename = read_entry.file_name().to_str().unwrap();
@@ -139,27 +554,96 @@ fn execute(source: PathBuf, assets_path: &str, base_url: &Url, ignored_exts: Vec
             continue;
         }
 
+        let html_dir = path.parent().and_then(Path::to_str).unwrap_or("");
+        let html_path_str = path.to_str().unwrap();
+
         let mut output = tempfile::NamedTempFile::new()?;
         let mut rewriter = HtmlRewriter::try_new(
             Settings {
                 element_content_handlers: vec![
                     element!("script[src]", |el| {
                         let src = el.get_attribute("src").expect("expected src to be present");
-                        let new_src = match_asset(&src, base_url, assets_path, &assets_hashes);
+                        check_broken(&broken, html_path_str, "src", &src, domains, assets_path, &assets_hashes);
+                        let new_src = match_asset(&src, domains, assets_path, &assets_hashes);
                         el.set_attribute("src", &new_src)?;
+                        if integrity {
+                            if let Some(asset) = resolve_local_path(&src, domains, assets_path).and_then(|p| assets_hashes.get(&p)) {
+                                el.set_attribute("integrity", &format!("sha384-{}", asset.sri))?;
+                                el.set_attribute("crossorigin", "anonymous")?;
+                            }
+                        }
                         Ok(())
                     }),
                     element!("link[rel='stylesheet'][href]", |el| {
                         let href = el.get_attribute("href").expect("expected href to be present");
-                        let new_href = match_asset(&href, base_url, assets_path, &assets_hashes);
+                        check_broken(&broken, html_path_str, "href", &href, domains, assets_path, &assets_hashes);
+                        let new_href = match_asset(&href, domains, assets_path, &assets_hashes);
                         el.set_attribute("href", &new_href)?;
+                        if integrity {
+                            if let Some(asset) = resolve_local_path(&href, domains, assets_path).and_then(|p| assets_hashes.get(&p)) {
+                                el.set_attribute("integrity", &format!("sha384-{}", asset.sri))?;
+                                el.set_attribute("crossorigin", "anonymous")?;
+                            }
+                        }
                         Ok(())
                     }),
                     element!("img[src]", |el| {
                         let src = el.get_attribute("src").expect("expected src to be present");
-                        let new_src = match_asset(&src, base_url, assets_path, &assets_hashes);
+                        check_broken(&broken, html_path_str, "src", &src, domains, assets_path, &assets_hashes);
+                        let new_src = match_asset(&src, domains, assets_path, &assets_hashes);
+                        el.set_attribute("src", &new_src)?;
+                        Ok(())
+                    }),
+                    element!("img[srcset]", |el| {
+                        let srcset = el.get_attribute("srcset").expect("expected srcset to be present");
+                        let new_srcset = rewrite_srcset(&srcset, domains, assets_path, &assets_hashes, &broken, html_path_str, "srcset");
+                        el.set_attribute("srcset", &new_srcset)?;
+                        Ok(())
+                    }),
+                    element!("source[srcset]", |el| {
+                        let srcset = el.get_attribute("srcset").expect("expected srcset to be present");
+                        let new_srcset = rewrite_srcset(&srcset, domains, assets_path, &assets_hashes, &broken, html_path_str, "srcset");
+                        el.set_attribute("srcset", &new_srcset)?;
+                        Ok(())
+                    }),
+                    element!("source[src]", |el| {
+                        let src = el.get_attribute("src").expect("expected src to be present");
+                        check_broken(&broken, html_path_str, "src", &src, domains, assets_path, &assets_hashes);
+                        let new_src = match_asset(&src, domains, assets_path, &assets_hashes);
+                        el.set_attribute("src", &new_src)?;
+                        Ok(())
+                    }),
+                    element!("video[src]", |el| {
+                        let src = el.get_attribute("src").expect("expected src to be present");
+                        check_broken(&broken, html_path_str, "src", &src, domains, assets_path, &assets_hashes);
+                        let new_src = match_asset(&src, domains, assets_path, &assets_hashes);
+                        el.set_attribute("src", &new_src)?;
+                        Ok(())
+                    }),
+                    element!("video[poster]", |el| {
+                        let poster = el.get_attribute("poster").expect("expected poster to be present");
+                        check_broken(&broken, html_path_str, "poster", &poster, domains, assets_path, &assets_hashes);
+                        let new_poster = match_asset(&poster, domains, assets_path, &assets_hashes);
+                        el.set_attribute("poster", &new_poster)?;
+                        Ok(())
+                    }),
+                    element!("audio[src]", |el| {
+                        let src = el.get_attribute("src").expect("expected src to be present");
+                        check_broken(&broken, html_path_str, "src", &src, domains, assets_path, &assets_hashes);
+                        let new_src = match_asset(&src, domains, assets_path, &assets_hashes);
                         el.set_attribute("src", &new_src)?;
                         Ok(())
+                    }),
+                    element!("[style]", |el| {
+                        let style = el.get_attribute("style").expect("expected style to be present");
+                        let new_style = process_css(&style, html_dir, domains, assets_path, &assets_hashes, &broken, html_path_str);
+                        el.set_attribute("style", &new_style)?;
+                        Ok(())
+                    }),
+                    text!("style", |t| {
+                        let new_content = process_css(t.as_str(), html_dir, domains, assets_path, &assets_hashes, &broken, html_path_str);
+                        t.replace(&new_content, ContentType::Text);
+                        Ok(())
                     })
                 ],
                 ..Settings::default()
@@ -180,13 +664,98 @@ fn execute(source: PathBuf, assets_path: &str, base_url: &Url, ignored_exts: Vec
         }
     }
 
+    let mut manifest = HashMap::new();
     for file in assets_hashes.keys() {
         let new_name = update_asset(file, &assets_hashes);
         if let Err(err) = fs::rename(file, &new_name) {
             println!("Error renaming {} => {}: {}", file, new_name, err);
             std::process::exit(1);
         }
+        manifest.insert(file.clone(), new_name);
+    }
+
+    if let Some(manifest_path) = manifest_path {
+        write_manifest(Path::new(manifest_path), &manifest)?;
+    }
+
+    let broken = broken.into_inner();
+    if !broken.is_empty() {
+        println!("Found {} dangling asset reference(s):", broken.len());
+        for reference in &broken {
+            println!("  {} [{}]: {}", reference.file, reference.attribute, reference.target);
+        }
+        if strict {
+            std::process::exit(1);
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_domains() -> DomainPolicy {
+        DomainPolicy::new(&Url::parse("http://example.com").unwrap(), HashSet::new(), HashSet::new())
+    }
+
+    #[test]
+    fn resolve_relative_handles_dot_segments() {
+        assert_eq!(resolve_relative("assets/css", "../img/bg.png"), "assets/img/bg.png");
+        assert_eq!(resolve_relative("assets/css", "./bg.png"), "assets/css/bg.png");
+        assert_eq!(resolve_relative("", "assets/img/bg.png"), "assets/img/bg.png");
+    }
+
+    #[test]
+    fn process_css_rewrites_url_case_insensitively() {
+        let domains = test_domains();
+        let broken = RefCell::new(Vec::new());
+        let mut assets = HashMap::new();
+        assets.insert(String::from("assets/img/bg.png"), AssetHash { hash: String::from("abc123"), sri: String::new() });
+
+        let css = "a{background:URL(/assets/img/bg.png)} b{background:url(/assets/img/bg.png)}";
+        let out = process_css(css, "assets/css", &domains, "assets", &assets, &broken, "assets/css/main.css");
+
+        assert_eq!(out, "a{background:url(assets/img/bg_abc123.png)} b{background:url(assets/img/bg_abc123.png)}");
+        assert!(broken.borrow().is_empty());
+    }
+
+    #[test]
+    fn process_css_rewrites_import_case_insensitively() {
+        let domains = test_domains();
+        let broken = RefCell::new(Vec::new());
+        let mut assets = HashMap::new();
+        assets.insert(String::from("assets/css/reset.css"), AssetHash { hash: String::from("final999"), sri: String::new() });
+
+        let css = "@Import url(/assets/css/reset.css);";
+        let out = process_css(css, "assets/css", &domains, "assets", &assets, &broken, "assets/css/main.css");
+
+        assert_eq!(out, "@import url(assets/css/reset_final999.css);");
+    }
+
+    #[test]
+    fn process_css_output_tracks_whatever_hash_it_is_given() {
+        // Regression guard for the cross-stylesheet ordering bug: process_css
+        // just looks up whatever it's handed in `assets`, so main.css's
+        // rewritten @import is only correct if reset.css's *converged* hash
+        // is what's in the map -- making sure the output execute() finally
+        // keeps was derived from converged hashes (it re-runs this until
+        // hashes stop changing) is what actually fixes the bug.
+        let domains = test_domains();
+        let broken = RefCell::new(Vec::new());
+        let css = "@import url(/assets/css/reset.css);";
+
+        let mut stale = HashMap::new();
+        stale.insert(String::from("assets/css/reset.css"), AssetHash { hash: String::from("stale111"), sri: String::new() });
+        let stale_out = process_css(css, "assets/css", &domains, "assets", &stale, &broken, "assets/css/main.css");
+
+        let mut converged = HashMap::new();
+        converged.insert(String::from("assets/css/reset.css"), AssetHash { hash: String::from("final999"), sri: String::new() });
+        let converged_out = process_css(css, "assets/css", &domains, "assets", &converged, &broken, "assets/css/main.css");
+
+        assert!(stale_out.contains("reset_stale111.css"));
+        assert!(converged_out.contains("reset_final999.css"));
+        assert_ne!(stale_out, converged_out);
+    }
+}